@@ -0,0 +1,310 @@
+use std::fmt;
+use std::iter::Peekable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Sym,
+    Var,
+    Num,
+    Str,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Equals,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Rule,
+    Shape,
+    Apply,
+    At,
+    First,
+    Reversed,
+    Undo,
+    Redo,
+    Load,
+    Done,
+    Commutative,
+    Associative,
+    Invalid,
+    End,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TokenKind::*;
+        match self {
+            Sym => write!(f, "symbol"),
+            Var => write!(f, "variable"),
+            Num => write!(f, "number"),
+            Str => write!(f, "string"),
+            OpenParen => write!(f, "open paren"),
+            CloseParen => write!(f, "close paren"),
+            Comma => write!(f, "comma"),
+            Equals => write!(f, "equals"),
+            Plus => write!(f, "'+'"),
+            Minus => write!(f, "'-'"),
+            Star => write!(f, "'*'"),
+            Slash => write!(f, "'/'"),
+            Caret => write!(f, "'^'"),
+            Rule => write!(f, "'rule'"),
+            Shape => write!(f, "'shape'"),
+            Apply => write!(f, "'apply'"),
+            At => write!(f, "'at'"),
+            First => write!(f, "'first'"),
+            Reversed => write!(f, "'reversed'"),
+            Undo => write!(f, "'undo'"),
+            Redo => write!(f, "'redo'"),
+            Load => write!(f, "'load'"),
+            Done => write!(f, "'done'"),
+            Commutative => write!(f, "'commutative'"),
+            Associative => write!(f, "'associative'"),
+            Invalid => write!(f, "invalid character"),
+            End => write!(f, "end of input"),
+        }
+    }
+}
+
+const TOKEN_KINDS: [TokenKind; 27] = {
+    use TokenKind::*;
+    [
+        Sym, Var, Num, Str, OpenParen, CloseParen, Comma, Equals,
+        Plus, Minus, Star, Slash, Caret,
+        Rule, Shape, Apply, At, First, Reversed, Undo, Redo, Load, Done,
+        Commutative, Associative, Invalid, End,
+    ]
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TokenKindSet(u32);
+
+impl TokenKindSet {
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn single(kind: TokenKind) -> Self {
+        Self::empty().set(kind)
+    }
+
+    pub fn set(self, kind: TokenKind) -> Self {
+        Self(self.0 | (1 << kind as u32))
+    }
+
+    pub fn contains(&self, kind: TokenKind) -> bool {
+        self.0 & (1 << kind as u32) != 0
+    }
+}
+
+impl fmt::Display for TokenKindSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for kind in TOKEN_KINDS {
+            if self.contains(kind) {
+                if !first {
+                    write!(f, " or ")?;
+                }
+                write!(f, "{}", kind)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Loc {
+    /// Name of the loaded script this token came from, or `None` for interactive input.
+    pub file: Option<String>,
+    pub row: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.row + 1, self.col + 1),
+            None => write!(f, "{}:{}", self.row + 1, self.col + 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub loc: Loc,
+}
+
+pub struct Lexer<Chars: Iterator<Item = char>> {
+    chars: Peekable<Chars>,
+    col: usize,
+    exhausted: bool,
+    file: Option<String>,
+    row: usize,
+}
+
+impl<Chars: Iterator<Item = char>> Lexer<Chars> {
+    pub fn from_iter(chars: Chars) -> Self {
+        Self::from_iter_with_loc(chars, None, 0)
+    }
+
+    /// Like [`Lexer::from_iter`], but stamps every token's [`Loc`] with the given file name and
+    /// row, for reporting errors in a loaded script rather than the interactive prompt.
+    pub fn from_iter_with_loc(chars: Chars, file: Option<String>, row: usize) -> Self {
+        Self {
+            chars: chars.peekable(),
+            col: 0,
+            exhausted: false,
+            file,
+            row,
+        }
+    }
+
+    fn loc(&self, col: usize) -> Loc {
+        Loc { file: self.file.clone(), row: self.row, col }
+    }
+
+    fn drop_char(&mut self) -> Option<char> {
+        self.col += 1;
+        self.chars.next()
+    }
+
+    fn trim_whitespaces(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.drop_char();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+fn keyword_or(text: &str, default: TokenKind) -> TokenKind {
+    match text {
+        "rule" => TokenKind::Rule,
+        "shape" => TokenKind::Shape,
+        "apply" => TokenKind::Apply,
+        "at" => TokenKind::At,
+        "first" => TokenKind::First,
+        "reversed" => TokenKind::Reversed,
+        "undo" => TokenKind::Undo,
+        "redo" => TokenKind::Redo,
+        "load" => TokenKind::Load,
+        "done" => TokenKind::Done,
+        "commutative" => TokenKind::Commutative,
+        "associative" => TokenKind::Associative,
+        _ => default,
+    }
+}
+
+impl<Chars: Iterator<Item = char>> Iterator for Lexer<Chars> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.trim_whitespaces();
+
+        let col = self.col;
+
+        match self.chars.peek() {
+            Some(c) if c.is_alphabetic() || *c == '_' || *c == '$' => {
+                let is_var = *c == '$';
+                if is_var {
+                    self.drop_char();
+                }
+
+                let mut text = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if c.is_alphanumeric() || *c == '_' {
+                        text.push(self.drop_char().unwrap());
+                    } else {
+                        break;
+                    }
+                }
+
+                let kind = if is_var {
+                    TokenKind::Var
+                } else {
+                    keyword_or(&text, TokenKind::Sym)
+                };
+
+                Some(Token { kind, text, loc: self.loc(col) })
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut text = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if c.is_ascii_digit() {
+                        text.push(self.drop_char().unwrap());
+                    } else {
+                        break;
+                    }
+                }
+                Some(Token { kind: TokenKind::Num, text, loc: self.loc(col) })
+            }
+            Some('(') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::OpenParen, text: "(".to_string(), loc: self.loc(col) })
+            }
+            Some(')') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::CloseParen, text: ")".to_string(), loc: self.loc(col) })
+            }
+            Some(',') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Comma, text: ",".to_string(), loc: self.loc(col) })
+            }
+            Some('=') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Equals, text: "=".to_string(), loc: self.loc(col) })
+            }
+            Some('+') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Plus, text: "+".to_string(), loc: self.loc(col) })
+            }
+            Some('-') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Minus, text: "-".to_string(), loc: self.loc(col) })
+            }
+            Some('*') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Star, text: "*".to_string(), loc: self.loc(col) })
+            }
+            Some('/') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Slash, text: "/".to_string(), loc: self.loc(col) })
+            }
+            Some('^') => {
+                self.drop_char();
+                Some(Token { kind: TokenKind::Caret, text: "^".to_string(), loc: self.loc(col) })
+            }
+            Some('"') => {
+                self.drop_char();
+                let mut text = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if *c == '"' {
+                        break;
+                    } else {
+                        text.push(self.drop_char().unwrap());
+                    }
+                }
+                self.drop_char();
+                Some(Token { kind: TokenKind::Str, text, loc: self.loc(col) })
+            }
+            Some(_) => {
+                let c = self.drop_char().unwrap();
+                Some(Token { kind: TokenKind::Invalid, text: c.to_string(), loc: self.loc(col) })
+            }
+            None => {
+                if self.exhausted {
+                    None
+                } else {
+                    self.exhausted = true;
+                    Some(Token { kind: TokenKind::End, text: "".to_string(), loc: self.loc(col) })
+                }
+            }
+        }
+    }
+}
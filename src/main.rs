@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::Peekable;
 use std::io::{stdin, stdout};
 use std::io::Write;
@@ -11,6 +11,7 @@ use lexer::*;
 #[derive(Debug, Clone, PartialEq)]
 enum Expr {
     Sym(String),
+    Var(String),
     Fun(String, Vec<Expr>)
 }
 
@@ -19,17 +20,90 @@ enum Error {
     UnexpectedToken(TokenKindSet, Token),
     RuleAlreadyExists(String, Loc, Loc),
     RuleDoesNotExist(String),
+    RuleNotReversible(String),
+    NoMatchAtPath(Vec<usize>),
+    NoMatchFound(String),
+    InvalidPathIndex(Token),
+    CouldNotLoadFile(String, String),
+    VariableFunctor(Token),
     AlreadyShaping,
     NoShapingInPlace,
+    NothingToUndo,
+    NothingToRedo,
+}
+
+/// Precedence and associativity of a binary operator functor, e.g. `("+", 2)` for `Fun("+", ..)`.
+/// Higher precedence binds tighter; `^` is right-associative, the rest are left-associative.
+fn operator_info(name: &str) -> Option<(u8, bool)> {
+    match name {
+        "+" => Some((1, false)),
+        "-" => Some((1, false)),
+        "*" => Some((2, false)),
+        "/" => Some((2, false)),
+        "^" => Some((3, true)),
+        _ => None,
+    }
+}
+
+fn operator_token_kind(kind: TokenKind) -> Option<&'static str> {
+    use TokenKind::*;
+    match kind {
+        Plus => Some("+"),
+        Minus => Some("-"),
+        Star => Some("*"),
+        Slash => Some("/"),
+        Caret => Some("^"),
+        _ => None,
+    }
+}
+
+/// The functors declared `commutative` and/or `associative` via the corresponding `Context`
+/// commands, consulted by `pattern_match` to relax structural matching for those operators.
+#[derive(Debug, Default)]
+struct AcOps {
+    commutative: HashSet<String>,
+    associative: HashSet<String>,
+}
+
+impl AcOps {
+    fn is_commutative(&self, name: &str) -> bool {
+        self.commutative.contains(name)
+    }
+
+    fn is_associative(&self, name: &str) -> bool {
+        self.associative.contains(name)
+    }
+}
+
+/// Parses the name of a functor for a `commutative`/`associative` declaration: either a plain
+/// symbol (e.g. `mul`) or one of the built-in infix operator tokens (e.g. `+`).
+fn expect_functor_name(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<String, Error> {
+    let token = lexer.next().expect("Completely exhausted lexer");
+    if token.kind == TokenKind::Sym || operator_token_kind(token.kind).is_some() {
+        Ok(token.text)
+    } else {
+        Err(Error::UnexpectedToken(TokenKindSet::single(TokenKind::Sym), token))
+    }
 }
 
 impl Expr {
-    fn parse_peekable(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<Self, Error> {
+    /// Parses an atom: a parenthesized group, a bare symbol/variable, or a function call.
+    /// Function-call parens are distinguished from grouping parens by immediately following
+    /// a symbol/variable with no space required; grouping parens stand on their own.
+    fn parse_atom(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<Self, Error> {
         use TokenKind::*;
         let name = lexer.next().expect("Completely exhausted lexer");
         match name.kind {
-            Sym => {
+            OpenParen => {
+                let inner = Self::parse_peekable(lexer)?;
+                expect_token_kind(lexer, TokenKindSet::single(CloseParen))?;
+                Ok(inner)
+            },
+            Sym | Var => {
                 if let Some(_) = lexer.next_if(|t| t.kind == OpenParen) {
+                    if name.kind == Var {
+                        return Err(Error::VariableFunctor(name));
+                    }
                     let mut args = Vec::new();
                     if let Some(_) = lexer.next_if(|t| t.kind == CloseParen) {
                         return Ok(Expr::Fun(name.text, args))
@@ -44,23 +118,88 @@ impl Expr {
                     } else {
                         Err(Error::UnexpectedToken(TokenKindSet::single(CloseParen), close_paren))
                     }
+                } else if name.kind == Var {
+                    Ok(Expr::Var(name.text))
                 } else {
                     Ok(Expr::Sym(name.text))
                 }
             },
-            _ => Err(Error::UnexpectedToken(TokenKindSet::single(Sym), name))
+            _ => Err(Error::UnexpectedToken(TokenKindSet::single(Sym).set(Var).set(OpenParen), name))
+        }
+    }
+
+    /// Precedence-climbing (Pratt) parser: parses an atom, then repeatedly extends it with
+    /// infix operators whose left binding power is at least `min_bp`, recursing with the
+    /// matching right binding power for the rhs. Operators stay ordinary `Fun` nodes, e.g.
+    /// `a + b * c` parses as `Fun("+", [a, Fun("*", [b, c])])`.
+    fn parse_expr_bp(lexer: &mut Peekable<impl Iterator<Item=Token>>, min_bp: u8) -> Result<Self, Error> {
+        let mut lhs = Self::parse_atom(lexer)?;
+        loop {
+            let op = match lexer.peek().and_then(|t| operator_token_kind(t.kind)) {
+                Some(op) => op,
+                None => break,
+            };
+            let (prec, right_assoc) = operator_info(op).expect("operator_token_kind implies operator_info");
+            let (left_bp, right_bp) = if right_assoc {
+                (2 * prec + 1, 2 * prec)
+            } else {
+                (2 * prec, 2 * prec + 1)
+            };
+            if left_bp < min_bp {
+                break;
+            }
+            lexer.next();
+            let rhs = Self::parse_expr_bp(lexer, right_bp)?;
+            lhs = Expr::Fun(op.to_string(), vec![lhs, rhs]);
         }
+        Ok(lhs)
     }
 
+    fn parse_peekable(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<Self, Error> {
+        Self::parse_expr_bp(lexer, 0)
+    }
+
+    #[allow(dead_code)]
     fn parse(lexer: &mut impl Iterator<Item=Token>) -> Result<Self, Error> {
         Self::parse_peekable(&mut lexer.peekable())
     }
+
+    /// Collects the names of every declared pattern variable (`Expr::Var`) occurring in `self`.
+    fn vars(&self) -> HashSet<String> {
+        fn go(expr: &Expr, vars: &mut HashSet<String>) {
+            match expr {
+                Expr::Sym(_) => {},
+                Expr::Var(name) => { vars.insert(name.clone()); },
+                Expr::Fun(_, args) => {
+                    for arg in args {
+                        go(arg, vars);
+                    }
+                },
+            }
+        }
+        let mut vars = HashSet::new();
+        go(self, &mut vars);
+        vars
+    }
 }
 
-impl fmt::Display for Expr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Expr {
+    /// Renders `self`, wrapping it in parens if its own operator precedence is below `min_prec`.
+    fn fmt_prec(&self, f: &mut fmt::Formatter, min_prec: u8) -> fmt::Result {
         match self {
             Expr::Sym(name) => write!(f, "{}", name),
+            Expr::Var(name) => write!(f, "${}", name),
+            Expr::Fun(name, args) if args.len() == 2 && operator_info(name).is_some() => {
+                let (prec, right_assoc) = operator_info(name).unwrap();
+                let (lhs_prec, rhs_prec) = if right_assoc { (prec + 1, prec) } else { (prec, prec + 1) };
+                let needs_parens = prec < min_prec;
+                if needs_parens { write!(f, "(")?; }
+                args[0].fmt_prec(f, lhs_prec)?;
+                write!(f, " {} ", name)?;
+                args[1].fmt_prec(f, rhs_prec)?;
+                if needs_parens { write!(f, ")")?; }
+                Ok(())
+            },
             Expr::Fun(name, args) => {
                 write!(f, "{}(", name)?;
                 for (i, arg) in args.iter().enumerate() {
@@ -73,17 +212,29 @@ impl fmt::Display for Expr {
     }
 }
 
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_prec(f, 0)
+    }
+}
+
 #[derive(Debug)]
 struct Rule {
     loc: Loc,
+    name: String,
     head: Expr,
     body: Expr,
+    /// Whether every variable in `head` also occurs in `body`, i.e. whether `apply_all_rev`/
+    /// `apply_at_rev` are well-defined (no dangling variables left unsubstituted).
+    reversible: bool,
 }
 
 fn substitute_bindings(bindings: &Bindings, expr: &Expr) -> Expr {
     use Expr::*;
     match expr {
-        Sym(name) => {
+        Sym(_) => expr.clone(),
+
+        Var(name) => {
             if let Some(value) = bindings.get(name) {
                 value.clone()
             } else {
@@ -92,16 +243,11 @@ fn substitute_bindings(bindings: &Bindings, expr: &Expr) -> Expr {
         },
 
         Fun(name, args) => {
-            let new_name = match bindings.get(name) {
-                Some(Sym(new_name)) => new_name.clone(),
-                None => name.clone(),
-                Some(_) => todo!("Report expected symbol in the place of the functor name"),
-            };
             let mut new_args = Vec::new();
             for arg in args {
                 new_args.push(substitute_bindings(bindings, &arg))
             }
-            Fun(new_name, new_args)
+            Fun(name.clone(), new_args)
         }
     }
 }
@@ -115,26 +261,107 @@ fn expect_token_kind(lexer: &mut Peekable<impl Iterator<Item=Token>>, kinds: Tok
     }
 }
 
-impl Rule {
-    fn apply_all(&self, expr: &Expr) -> Expr {
-        if let Some(bindings) = pattern_match(&self.head, expr) {
-            substitute_bindings(&bindings, &self.body)
-        } else {
-            use Expr::*;
-            match expr {
-                Sym(_) => expr.clone(),
-                Fun(name, args) => {
-                    let mut new_args = Vec::new();
-                    for arg in args {
-                        new_args.push(self.apply_all(arg))
-                    }
-                    Fun(name.clone(), new_args)
+/// Rewrites every occurrence of `pattern` in `expr` top-down, substituting `body`.
+fn rewrite_all(pattern: &Expr, body: &Expr, expr: &Expr, ac: &AcOps) -> Expr {
+    if let Some(bindings) = pattern_match(pattern, expr, ac) {
+        substitute_bindings(&bindings, body)
+    } else {
+        use Expr::*;
+        match expr {
+            Sym(_) | Var(_) => expr.clone(),
+            Fun(name, args) => {
+                let mut new_args = Vec::new();
+                for arg in args {
+                    new_args.push(rewrite_all(pattern, body, arg, ac))
                 }
+                Fun(name.clone(), new_args)
             }
         }
     }
 }
 
+/// Rewrites the single occurrence of `pattern` addressed by `path` (a sequence of argument
+/// indices, empty meaning the root), substituting `body`, or `None` if `pattern` does not match there.
+fn rewrite_at(pattern: &Expr, body: &Expr, expr: &Expr, path: &[usize], ac: &AcOps) -> Option<Expr> {
+    match path.split_first() {
+        None => {
+            let bindings = pattern_match(pattern, expr, ac)?;
+            Some(substitute_bindings(&bindings, body))
+        },
+        Some((&i, rest)) => {
+            if let Expr::Fun(name, args) = expr {
+                let new_arg = rewrite_at(pattern, body, args.get(i)?, rest, ac)?;
+                let mut new_args = args.clone();
+                new_args[i] = new_arg;
+                Some(Expr::Fun(name.clone(), new_args))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Finds the paths of every occurrence in `expr` where `pattern` matches, in
+/// leftmost-outermost order, so `matches[0]` is the "first" match.
+fn find_pattern_matches(pattern: &Expr, expr: &Expr, ac: &AcOps) -> Vec<Vec<usize>> {
+    fn go(pattern: &Expr, expr: &Expr, path: &mut Vec<usize>, matches: &mut Vec<Vec<usize>>, ac: &AcOps) {
+        if pattern_match(pattern, expr, ac).is_some() {
+            matches.push(path.clone());
+        }
+        if let Expr::Fun(_, args) = expr {
+            for (i, arg) in args.iter().enumerate() {
+                path.push(i);
+                go(pattern, arg, path, matches, ac);
+                path.pop();
+            }
+        }
+    }
+    let mut matches = Vec::new();
+    go(pattern, expr, &mut Vec::new(), &mut matches, ac);
+    matches
+}
+
+impl Rule {
+    fn apply_all(&self, expr: &Expr, ac: &AcOps) -> Expr {
+        rewrite_all(&self.head, &self.body, expr, ac)
+    }
+
+    fn apply_at(&self, expr: &Expr, path: &[usize], ac: &AcOps) -> Result<Expr, Error> {
+        rewrite_at(&self.head, &self.body, expr, path, ac)
+            .ok_or_else(|| Error::NoMatchAtPath(path.to_vec()))
+    }
+
+    fn find_matches(&self, expr: &Expr, ac: &AcOps) -> Vec<Vec<usize>> {
+        find_pattern_matches(&self.head, expr, ac)
+    }
+
+    /// Applies the rule backward (`body` -> `head`), for `apply ruleName reversed`.
+    /// Only well-defined when every variable in `head` also occurs in `body`.
+    fn apply_all_rev(&self, expr: &Expr, ac: &AcOps) -> Result<Expr, Error> {
+        if !self.reversible {
+            return Err(Error::RuleNotReversible(self.name.clone()));
+        }
+        Ok(rewrite_all(&self.body, &self.head, expr, ac))
+    }
+
+    /// The `apply_at` equivalent of [`Rule::apply_all_rev`].
+    fn apply_at_rev(&self, expr: &Expr, path: &[usize], ac: &AcOps) -> Result<Expr, Error> {
+        if !self.reversible {
+            return Err(Error::RuleNotReversible(self.name.clone()));
+        }
+        rewrite_at(&self.body, &self.head, expr, path, ac)
+            .ok_or_else(|| Error::NoMatchAtPath(path.to_vec()))
+    }
+
+    /// The `find_matches` equivalent for the backward direction (matching against `body`).
+    fn find_matches_rev(&self, expr: &Expr, ac: &AcOps) -> Result<Vec<Vec<usize>>, Error> {
+        if !self.reversible {
+            return Err(Error::RuleNotReversible(self.name.clone()));
+        }
+        Ok(find_pattern_matches(&self.body, expr, ac))
+    }
+}
+
 impl fmt::Display for Rule {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} = {}", self.head, self.body)
@@ -143,37 +370,134 @@ impl fmt::Display for Rule {
 
 type Bindings = HashMap<String, Expr>;
 
-fn pattern_match(pattern: &Expr, value: &Expr) -> Option<Bindings> {
-    fn pattern_match_impl(pattern: &Expr, value: &Expr, bindings: &mut Bindings) -> bool {
-        use Expr::*;
-        match (pattern, value) {
-            (Sym(name), _) => {
-                if let Some(bound_value) = bindings.get(name) {
-                    bound_value == value
-                } else {
-                    bindings.insert(name.clone(), value.clone());
-                    true
-                }
-            },
-            (Fun(name1, args1), Fun(name2, args2)) => {
-                if name1 == name2 && args1.len() == args2.len() {
-                    for i in 0..args1.len() {
-                        if !pattern_match_impl(&args1[i], &args2[i], bindings) {
-                            return false;
+/// Flattens the immediate operands of `op` out of `expr`, which is expected to be a
+/// `Fun(op, ..)` node: recurses into nested `Fun(op, ..)` arguments when `op` is declared
+/// associative, so e.g. `(a + b) + c` and `a + (b + c)` both flatten to `[a, b, c]`.
+fn flatten_operands(op: &str, expr: &Expr, ac: &AcOps) -> Vec<Expr> {
+    match expr {
+        Expr::Fun(name, args) if name == op => {
+            let mut operands = Vec::new();
+            for arg in args {
+                if ac.is_associative(op) {
+                    if let Expr::Fun(inner_name, _) = arg {
+                        if inner_name == op {
+                            operands.extend(flatten_operands(op, arg, ac));
+                            continue;
                         }
                     }
-                    true
-                } else {
-                    false
                 }
-            },
-            _ => false,
+                operands.push(arg.clone());
+            }
+            operands
+        },
+        _ => vec![expr.clone()],
+    }
+}
+
+/// Rebuilds a (left-associated) `Fun(op, ..)` tree out of two or more flattened operands.
+fn combine_operands(op: &str, mut operands: Vec<Expr>) -> Expr {
+    let first = operands.remove(0);
+    operands.into_iter().fold(first, |acc, operand| Expr::Fun(op.to_string(), vec![acc, operand]))
+}
+
+/// Matches flattened `pattern_operands` against `value_operands` positionally (operand `i`
+/// against operand `i`), the only sensible order for an operator that is associative but not
+/// commutative. A trailing bare variable may absorb every leftover value operand as "the rest".
+fn match_operands_positional(pattern_operands: &[Expr], value_operands: &[Expr], op: &str, bindings: &mut Bindings, ac: &AcOps) -> bool {
+    match pattern_operands.split_first() {
+        None => value_operands.is_empty(),
+        Some((first, rest)) => {
+            if rest.is_empty() && value_operands.len() > 1 {
+                if let Expr::Var(_) = first {
+                    let combined = combine_operands(op, value_operands.to_vec());
+                    return pattern_match_impl(first, &combined, bindings, ac);
+                }
+                return false;
+            }
+            match value_operands.split_first() {
+                Some((value_first, value_rest)) => {
+                    pattern_match_impl(first, value_first, bindings, ac)
+                        && match_operands_positional(rest, value_rest, op, bindings, ac)
+                },
+                None => false,
+            }
+        }
+    }
+}
+
+/// Matches flattened `pattern_operands` against the multiset `value_operands`, trying every
+/// consistent assignment of pattern operands to value operands (backtracking on conflicts),
+/// the search needed for a commutative operator. A trailing bare variable may absorb every
+/// leftover value operand as "the rest".
+fn match_operands_multiset(pattern_operands: &[Expr], value_operands: Vec<Expr>, op: &str, bindings: &mut Bindings, ac: &AcOps) -> bool {
+    let (first, rest) = match pattern_operands.split_first() {
+        Some(split) => split,
+        None => return value_operands.is_empty(),
+    };
+
+    if rest.is_empty() && value_operands.len() > 1 {
+        if let Expr::Var(_) = first {
+            let combined = combine_operands(op, value_operands);
+            return pattern_match_impl(first, &combined, bindings, ac);
         }
+        return false;
     }
 
+    for i in 0..value_operands.len() {
+        let mut trial_bindings = bindings.clone();
+        if pattern_match_impl(first, &value_operands[i], &mut trial_bindings, ac) {
+            let mut remaining = value_operands.clone();
+            remaining.remove(i);
+            if match_operands_multiset(rest, remaining, op, &mut trial_bindings, ac) {
+                *bindings = trial_bindings;
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn pattern_match_impl(pattern: &Expr, value: &Expr, bindings: &mut Bindings, ac: &AcOps) -> bool {
+    use Expr::*;
+    match (pattern, value) {
+        (Var(name), _) => {
+            if let Some(bound_value) = bindings.get(name) {
+                bound_value == value
+            } else {
+                bindings.insert(name.clone(), value.clone());
+                true
+            }
+        },
+        (Sym(name1), Sym(name2)) => name1 == name2,
+        (Fun(name1, _), Fun(name2, _)) if name1 == name2 && (ac.is_commutative(name1) || ac.is_associative(name1)) => {
+            let pattern_operands = flatten_operands(name1, pattern, ac);
+            let value_operands = flatten_operands(name1, value, ac);
+            if ac.is_commutative(name1) {
+                match_operands_multiset(&pattern_operands, value_operands, name1, bindings, ac)
+            } else {
+                match_operands_positional(&pattern_operands, &value_operands, name1, bindings, ac)
+            }
+        },
+        (Fun(name1, args1), Fun(name2, args2)) => {
+            if name1 == name2 && args1.len() == args2.len() {
+                for i in 0..args1.len() {
+                    if !pattern_match_impl(&args1[i], &args2[i], bindings, ac) {
+                        return false;
+                    }
+                }
+                true
+            } else {
+                false
+            }
+        },
+        _ => false,
+    }
+}
+
+fn pattern_match(pattern: &Expr, value: &Expr, ac: &AcOps) -> Option<Bindings> {
     let mut bindings = HashMap::new();
 
-    if pattern_match_impl(pattern, value, &mut bindings) {
+    if pattern_match_impl(pattern, value, &mut bindings, ac) {
         Some(bindings)
     } else {
         None
@@ -219,10 +543,17 @@ mod tests {
 
     #[test]
     pub fn rule_apply_all() {
-        // swap(pair(a, b)) = pair(b, a)
+        // swap(pair($a, $b)) = pair($b, $a)
+        let head = Expr::Fun("swap".to_string(), vec![
+            Expr::Fun("pair".to_string(), vec![Expr::Var("a".to_string()), Expr::Var("b".to_string())])
+        ]);
+        let body = Expr::Fun("pair".to_string(), vec![Expr::Var("b".to_string()), Expr::Var("a".to_string())]);
         let swap = Rule {
-            head: expr!(swap(pair(a, b))),
-            body: expr!(pair(b, a)),
+            loc: Loc::default(),
+            name: "swap".to_string(),
+            reversible: head.vars().is_subset(&body.vars()),
+            head,
+            body,
         };
 
         let input = expr! {
@@ -235,14 +566,226 @@ mod tests {
                 pair(z(d), q(c)))
         };
 
-        assert_eq!(swap.apply_all(&input), expected);
+        assert_eq!(swap.apply_all(&input, &AcOps::default()), expected);
+    }
+
+    #[test]
+    pub fn pattern_match_rejects_literal_symbol_mismatch() {
+        // add($x, zero) = $x -- "zero" is a literal symbol, not a pattern variable.
+        let head = Expr::Fun("add".to_string(), vec![Expr::Var("x".to_string()), Expr::Sym("zero".to_string())]);
+        let body = Expr::Var("x".to_string());
+        let rule = Rule {
+            loc: Loc::default(),
+            name: "add".to_string(),
+            reversible: head.vars().is_subset(&body.vars()),
+            head,
+            body,
+        };
+        let ac = AcOps::default();
+
+        assert_eq!(rule.apply_all(&expr! { add(a, zero) }, &ac), expr! { a });
+
+        // "one" does not literally match the required "zero", so the rule must not fire.
+        assert_eq!(rule.apply_all(&expr! { add(a, one) }, &ac), expr! { add(a, one) });
+    }
+
+    #[test]
+    pub fn rule_apply_at_and_find_matches() {
+        // inc($x) = plus($x, one)
+        let head = Expr::Fun("inc".to_string(), vec![Expr::Var("x".to_string())]);
+        let body = Expr::Fun("plus".to_string(), vec![Expr::Var("x".to_string()), Expr::Sym("one".to_string())]);
+        let rule = Rule {
+            loc: Loc::default(),
+            name: "inc".to_string(),
+            reversible: head.vars().is_subset(&body.vars()),
+            head,
+            body,
+        };
+        let ac = AcOps::default();
+
+        let input = expr! { pair(inc(a), inc(b)) };
+
+        assert_eq!(rule.find_matches(&input, &ac), vec![vec![0], vec![1]]);
+
+        let expected = expr! { pair(plus(a, one), inc(b)) };
+        assert_eq!(rule.apply_at(&input, &[0], &ac).unwrap(), expected);
+
+        assert!(matches!(rule.apply_at(&input, &[5], &ac), Err(Error::NoMatchAtPath(_))));
+    }
+
+    #[test]
+    pub fn rule_apply_all_rev() {
+        let ac = AcOps::default();
+
+        // double($x) = plus($x, $x), reversible since $x also occurs in the body
+        let head = Expr::Fun("double".to_string(), vec![Expr::Var("x".to_string())]);
+        let body = Expr::Fun("plus".to_string(), vec![Expr::Var("x".to_string()), Expr::Var("x".to_string())]);
+        let rule = Rule {
+            loc: Loc::default(),
+            name: "double".to_string(),
+            reversible: head.vars().is_subset(&body.vars()),
+            head,
+            body,
+        };
+        assert_eq!(rule.apply_all_rev(&expr! { plus(a, a) }, &ac).unwrap(), expr! { double(a) });
+
+        // const($x) = zero, not reversible since $x does not occur in the body
+        let head = Expr::Fun("const".to_string(), vec![Expr::Var("x".to_string())]);
+        let body = Expr::Sym("zero".to_string());
+        let bad_rule = Rule {
+            loc: Loc::default(),
+            name: "const".to_string(),
+            reversible: head.vars().is_subset(&body.vars()),
+            head,
+            body,
+        };
+        assert!(matches!(bad_rule.apply_all_rev(&expr! { zero }, &ac), Err(Error::RuleNotReversible(_))));
+    }
+
+    #[test]
+    pub fn context_load_replays_script() {
+        let path = std::env::temp_dir().join("noq_test_context_load_replays_script.noq");
+        std::fs::write(&path, "rule idz plus(a, zero) = a\nshape plus(a, zero)\napply idz\ndone\n").unwrap();
+
+        let mut context = Context::default();
+        let command = format!("load \"{}\"", path.display());
+        let mut lexer = Lexer::from_iter(command.chars()).peekable();
+        let result = context.process_command(&mut lexer);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_ok());
+        assert!(context.undo_stack.is_empty());
+        assert!(context.rules.contains_key("idz"));
+    }
+
+    #[test]
+    pub fn expr_parses_and_prints_infix_with_precedence() {
+        let mut lexer = Lexer::from_iter("a + b * c - d".chars()).peekable();
+        let expr = Expr::parse_peekable(&mut lexer).unwrap();
+        assert_eq!(
+            expr,
+            Expr::Fun("-".to_string(), vec![
+                Expr::Fun("+".to_string(), vec![
+                    Expr::Sym("a".to_string()),
+                    Expr::Fun("*".to_string(), vec![Expr::Sym("b".to_string()), Expr::Sym("c".to_string())]),
+                ]),
+                Expr::Sym("d".to_string()),
+            ])
+        );
+        assert_eq!(expr.to_string(), "a + b * c - d");
+
+        let mut lexer = Lexer::from_iter("(a + b) * c".chars()).peekable();
+        let expr = Expr::parse_peekable(&mut lexer).unwrap();
+        assert_eq!(expr.to_string(), "(a + b) * c");
+    }
+
+    #[test]
+    pub fn context_undo_redo() {
+        fn run(context: &mut Context, command: &str) -> Result<(), Error> {
+            let mut lexer = Lexer::from_iter(command.chars()).peekable();
+            context.process_command(&mut lexer)
+        }
+
+        let mut context = Context::default();
+        run(&mut context, "rule wrapit $x = wrap($x)").unwrap();
+        run(&mut context, "shape a").unwrap();
+        run(&mut context, "apply wrapit").unwrap();
+        assert_eq!(context.undo_stack.last(), Some(&expr! { wrap(a) }));
+
+        run(&mut context, "undo").unwrap();
+        assert_eq!(context.undo_stack.last(), Some(&expr! { a }));
+        assert_eq!(context.redo_stack.len(), 1);
+
+        assert!(matches!(run(&mut context, "undo"), Err(Error::NothingToUndo)));
+
+        run(&mut context, "redo").unwrap();
+        assert_eq!(context.undo_stack.last(), Some(&expr! { wrap(a) }));
+        assert!(context.redo_stack.is_empty());
+    }
+
+    #[test]
+    pub fn pattern_match_associative_commutative() {
+        fn parse(source: &str) -> Expr {
+            let mut lexer = Lexer::from_iter(source.chars()).peekable();
+            Expr::parse_peekable(&mut lexer).unwrap()
+        }
+
+        let mut ac = AcOps::default();
+        ac.commutative.insert("+".to_string());
+        ac.associative.insert("+".to_string());
+
+        // zero + $rest matches a regrouped, reordered chain, with $rest absorbing the leftovers.
+        let pattern = Expr::Fun("+".to_string(), vec![Expr::Sym("zero".to_string()), Expr::Var("rest".to_string())]);
+        let bindings = pattern_match(&pattern, &parse("(a + b) + zero"), &ac).unwrap();
+        assert_eq!(bindings.get("rest"), Some(&combine_operands("+", vec![
+            Expr::Sym("a".to_string()),
+            Expr::Sym("b".to_string()),
+        ])));
+
+        // $x + $x only matches when both operands are the same already-bound value.
+        let dup_pattern = Expr::Fun("+".to_string(), vec![Expr::Var("x".to_string()), Expr::Var("x".to_string())]);
+        assert!(pattern_match(&dup_pattern, &parse("a + a"), &ac).is_some());
+        assert!(pattern_match(&dup_pattern, &parse("a + b"), &ac).is_none());
+    }
+
+    #[test]
+    pub fn pattern_match_associative_only_is_order_sensitive() {
+        fn parse(source: &str) -> Expr {
+            let mut lexer = Lexer::from_iter(source.chars()).peekable();
+            Expr::parse_peekable(&mut lexer).unwrap()
+        }
+
+        let mut ac = AcOps::default();
+        ac.associative.insert("+".to_string());
+
+        // A trailing $rest absorbs every leftover operand no matter how the chain is regrouped.
+        let pattern = Expr::Fun("+".to_string(), vec![Expr::Sym("a".to_string()), Expr::Var("rest".to_string())]);
+        let bindings = pattern_match(&pattern, &parse("(a + b) + c"), &ac).unwrap();
+        assert_eq!(bindings.get("rest"), Some(&combine_operands("+", vec![
+            Expr::Sym("b".to_string()),
+            Expr::Sym("c".to_string()),
+        ])));
+
+        // Without commutative, operand order is still significant: "zero" must be the first
+        // operand, not merely present somewhere in the multiset.
+        let reordered_pattern = Expr::Fun("+".to_string(), vec![Expr::Sym("zero".to_string()), Expr::Var("rest".to_string())]);
+        assert!(pattern_match(&reordered_pattern, &parse("a + zero"), &ac).is_none());
+    }
+
+    #[test]
+    pub fn context_commutative_associative_directives_enable_ac_matching() {
+        fn run(context: &mut Context, command: &str) -> Result<(), Error> {
+            let mut lexer = Lexer::from_iter(command.chars()).peekable();
+            context.process_command(&mut lexer)
+        }
+
+        let mut context = Context::default();
+        run(&mut context, "commutative +").unwrap();
+        run(&mut context, "associative +").unwrap();
+        assert!(context.ac.is_commutative("+"));
+        assert!(context.ac.is_associative("+"));
+
+        run(&mut context, "rule addz zero + $rest = $rest").unwrap();
+        run(&mut context, "shape (a + b) + zero").unwrap();
+        run(&mut context, "apply addz").unwrap();
+        assert_eq!(
+            context.undo_stack.last(),
+            Some(&Expr::Fun("+".to_string(), vec![Expr::Sym("a".to_string()), Expr::Sym("b".to_string())]))
+        );
     }
 }
 
 #[derive(Default)]
 struct Context {
     rules: HashMap<String, Rule>,
-    current_expr: Option<Expr>
+    /// History of expressions visited in the current `shape` session; the last entry, if any,
+    /// is the current expression. Empty means no shaping is in place.
+    undo_stack: Vec<Expr>,
+    /// Expressions popped by `undo`, replayed by `redo`. Cleared whenever a new step is taken.
+    redo_stack: Vec<Expr>,
+    /// Functors declared `commutative`/`associative`, consulted during matching.
+    ac: AcOps,
 }
 
 impl Context {
@@ -251,7 +794,12 @@ impl Context {
             .set(TokenKind::Rule)
             .set(TokenKind::Shape)
             .set(TokenKind::Apply)
-            .set(TokenKind::Done);
+            .set(TokenKind::Undo)
+            .set(TokenKind::Redo)
+            .set(TokenKind::Load)
+            .set(TokenKind::Done)
+            .set(TokenKind::Commutative)
+            .set(TokenKind::Associative);
         let keyword = expect_token_kind(lexer, expected_tokens)?;
         match keyword.kind {
             TokenKind::Rule => {
@@ -262,55 +810,185 @@ impl Context {
                 let head = Expr::parse_peekable(lexer)?;
                 expect_token_kind(lexer, TokenKindSet::single(TokenKind::Equals))?;
                 let body = Expr::parse_peekable(lexer)?;
+                let reversible = head.vars().is_subset(&body.vars());
                 let rule = Rule {
                     loc: keyword.loc,
+                    name: name.text.clone(),
                     head,
                     body,
+                    reversible,
                 };
                 println!("Defined rule {}", &rule);
                 self.rules.insert(name.text, rule);
             }
             TokenKind::Shape => {
-                if let Some(_) = self.current_expr {
+                if !self.undo_stack.is_empty() {
                     return Err(Error::AlreadyShaping)
                 }
 
                 let expr = Expr::parse_peekable(lexer)?;
                 println!("Shaping {}", &expr);
-                self.current_expr = Some(expr);
+                self.undo_stack.push(expr);
+                self.redo_stack.clear();
             },
             TokenKind::Apply => {
-                if let Some(expr) = &self.current_expr {
+                if let Some(expr) = self.undo_stack.last() {
                     let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
-                    if let Some(rule) = self.rules.get(&name.text) {
-                        let new_expr = rule.apply_all(&expr);
-                        println!("{}", &new_expr);
-                        self.current_expr = Some(new_expr);
+                    let rule = self.rules.get(&name.text)
+                        .ok_or_else(|| Error::RuleDoesNotExist(name.text.clone()))?;
+                    let reversed = lexer.next_if(|t| t.kind == TokenKind::Reversed).is_some();
+                    let new_expr = if let Some(_) = lexer.next_if(|t| t.kind == TokenKind::At) {
+                        let mut path = Vec::new();
+                        while let Some(num) = lexer.next_if(|t| t.kind == TokenKind::Num) {
+                            let index = num.text.parse::<usize>()
+                                .map_err(|_| Error::InvalidPathIndex(num))?;
+                            path.push(index);
+                        }
+                        if reversed { rule.apply_at_rev(&expr, &path, &self.ac)? } else { rule.apply_at(&expr, &path, &self.ac)? }
+                    } else if let Some(_) = lexer.next_if(|t| t.kind == TokenKind::First) {
+                        let matches = if reversed { rule.find_matches_rev(&expr, &self.ac)? } else { rule.find_matches(&expr, &self.ac) };
+                        let path = matches.into_iter().next()
+                            .ok_or_else(|| Error::NoMatchFound(name.text.clone()))?;
+                        if reversed { rule.apply_at_rev(&expr, &path, &self.ac)? } else { rule.apply_at(&expr, &path, &self.ac)? }
+                    } else if reversed {
+                        rule.apply_all_rev(&expr, &self.ac)?
                     } else {
-                        return Err(Error::RuleDoesNotExist(name.text));
-                    }
+                        rule.apply_all(&expr, &self.ac)
+                    };
+                    println!("{}", &new_expr);
+                    self.undo_stack.push(new_expr);
+                    self.redo_stack.clear();
                 } else {
                     return Err(Error::NoShapingInPlace);
                 }
             }
+            TokenKind::Undo => {
+                if self.undo_stack.len() <= 1 {
+                    return Err(Error::NothingToUndo);
+                }
+                self.redo_stack.push(self.undo_stack.pop().unwrap());
+                println!("{}", self.undo_stack.last().expect("just checked len() > 1"));
+            }
+            TokenKind::Redo => {
+                let expr = self.redo_stack.pop().ok_or(Error::NothingToRedo)?;
+                println!("{}", &expr);
+                self.undo_stack.push(expr);
+            }
             TokenKind::Done => {
-                if let Some(expr) = &self.current_expr {
-                    println!("Finished shaping expression {}", expr);
-                    self.current_expr = None
+                if let Some(expr) = self.undo_stack.last() {
+                    let steps = self.undo_stack.len() - 1;
+                    println!("Finished shaping expression {} in {} step(s)", expr, steps);
+                    self.undo_stack.clear();
+                    self.redo_stack.clear();
                 } else {
                     return Err(Error::NoShapingInPlace)
                 }
             }
+            TokenKind::Load => {
+                let path = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Str))?;
+                let source = std::fs::read_to_string(&path.text)
+                    .map_err(|err| Error::CouldNotLoadFile(path.text.clone(), err.to_string()))?;
+                for (row, line) in source.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let mut line_lexer = Lexer::from_iter_with_loc(line.chars(), Some(path.text.clone()), row).peekable();
+                    self.process_command(&mut line_lexer)
+                        .and_then(|()| expect_token_kind(&mut line_lexer, TokenKindSet::single(TokenKind::End)))?;
+                }
+            }
+            TokenKind::Commutative => {
+                let name = expect_functor_name(lexer)?;
+                println!("{} is now commutative", &name);
+                self.ac.commutative.insert(name);
+            }
+            TokenKind::Associative => {
+                let name = expect_functor_name(lexer)?;
+                println!("{} is now associative", &name);
+                self.ac.associative.insert(name);
+            }
             _ => unreachable!("Expected {} but got {}", expected_tokens, keyword.kind),
         }
         Ok(())
     }
 }
 
+/// The `Loc` to draw the caret under, for the error variants that carry one.
+fn error_loc(err: &Error) -> Option<&Loc> {
+    match err {
+        Error::UnexpectedToken(_, actual) => Some(&actual.loc),
+        Error::RuleAlreadyExists(_, new_loc, _) => Some(new_loc),
+        Error::InvalidPathIndex(token) => Some(&token.loc),
+        Error::VariableFunctor(token) => Some(&token.loc),
+        _ => None,
+    }
+}
+
+fn error_message(err: &Error) -> String {
+    match err {
+        Error::UnexpectedToken(expected, actual) => {
+            format!("expected {} but got {} '{}'", expected, actual.kind, actual.text)
+        },
+        Error::RuleAlreadyExists(name, ..) => format!("redefinition of existing rule {}", name),
+        Error::RuleDoesNotExist(name) => format!("rule {} does not exist", name),
+        Error::RuleNotReversible(name) => format!(
+            "rule {} is not reversible: it has a variable in its head that does not occur in its body",
+            name
+        ),
+        Error::NoMatchAtPath(path) => format!("no match at path {:?}", path),
+        Error::InvalidPathIndex(token) => format!("path index '{}' is too large", token.text),
+        Error::NoMatchFound(name) => format!("rule {} does not match anywhere in the expression", name),
+        Error::CouldNotLoadFile(path, reason) => format!("could not load {}: {}", path, reason),
+        Error::VariableFunctor(token) => format!(
+            "'${}' cannot be used as a function name: pattern variables may not occur in functor position",
+            token.text
+        ),
+        Error::AlreadyShaping => format!(
+            "already shaping an expression. Finish the current shaping with {} first.",
+            TokenKind::Done
+        ),
+        Error::NoShapingInPlace => "no shaping in place.".to_string(),
+        Error::NothingToUndo => "nothing to undo.".to_string(),
+        Error::NothingToRedo => "nothing to redo.".to_string(),
+    }
+}
+
+/// Reports `err` to stderr, with a caret under the offending token when its `Loc` has one.
+/// `prompt` is the text that was printed before the user's input on this line (so the caret
+/// lines up); pass `""` when there was no prompt, e.g. while replaying a loaded script. For a
+/// loaded script there is no REPL echo of the line to line the caret up with, so the offending
+/// source line is re-read from `loc.file` and printed above the caret.
+fn report_error(err: &Error, prompt: &str) {
+    if let Some(loc) = error_loc(err) {
+        if let Some(file) = &loc.file {
+            eprintln!("{}:", loc);
+            if let Ok(source) = std::fs::read_to_string(file) {
+                if let Some(line) = source.lines().nth(loc.row) {
+                    eprintln!("{}", line);
+                }
+            }
+        }
+        eprintln!("{:>width$}^", "", width = prompt.len() + loc.col);
+    }
+    eprintln!("ERROR: {}", error_message(err));
+}
+
 fn main() {
     let mut context = Context::default();
-    let mut command = String::new();
 
+    if let Some(path) = std::env::args().nth(1) {
+        let command = format!("load \"{}\"", path);
+        let mut lexer = Lexer::from_iter(command.chars()).peekable();
+        let result = context.process_command(&mut lexer)
+            .and_then(|()| expect_token_kind(&mut lexer, TokenKindSet::single(TokenKind::End)));
+        if let Err(err) = result {
+            report_error(&err, "");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut command = String::new();
     let prompt = "> ";
 
     loop {
@@ -321,26 +999,8 @@ fn main() {
         let mut lexer = Lexer::from_iter(command.chars()).peekable();
         let result = context.process_command(&mut lexer)
             .and_then(|()| expect_token_kind(&mut lexer, TokenKindSet::single(TokenKind::End)));
-        match result {
-            Err(Error::UnexpectedToken(expected, actual)) => {
-                eprintln!("{:>width$}^", "", width=prompt.len() + actual.loc.col);
-                eprintln!("ERROR: expected {} but got {} '{}'", expected, actual.kind, actual.text);
-            }
-            Err(Error::RuleAlreadyExists(name, new_loc, _old_loc)) => {
-                eprintln!("{:>width$}^", "", width=prompt.len() + new_loc.col);
-                eprintln!("ERROR: redefinition of existing rule {}", name);
-            }
-            Err(Error::AlreadyShaping) => {
-                eprintln!("ERROR: already shaping an expression. Finish the current shaping with {} first.",
-                          TokenKind::Done);
-            }
-            Err(Error::NoShapingInPlace) => {
-                eprintln!("ERROR: no shaping in place.");
-            }
-            Err(Error::RuleDoesNotExist(name)) => {
-                eprintln!("ERROR: rule {} does not exist", name);
-            }
-            Ok(_) => {}
+        if let Err(err) = result {
+            report_error(&err, prompt);
         }
     }
 }